@@ -0,0 +1,2 @@
+//! Raw ETW types used by the native wrappers.
+pub(crate) mod stack_walk;
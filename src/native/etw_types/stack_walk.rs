@@ -0,0 +1,107 @@
+//! Typed parser for `StackWalk_Event` records.
+//!
+//! When stack-walk capture is enabled (see [`crate::native::evntrace::enable_stack_walk`]), ETW
+//! emits a separate `StackWalk_Event` for each sampled/traced event, carrying the call stack that
+//! was captured at that point. This event's layout is fixed by the kernel and is not described by
+//! a manifest, so it gets its own small parser rather than going through the generic schema path.
+use windows::Win32::Foundation::FILETIME;
+
+/// A parsed `StackWalk_Event` record: the call stack captured at `event_timestamp`, for
+/// `stack_process`/`stack_thread`, correlating it with the event that triggered the capture.
+#[derive(Debug, Clone)]
+pub struct StackWalkEvent {
+    pub event_timestamp: FILETIME,
+    pub stack_process: u32,
+    pub stack_thread: u32,
+    /// Return addresses, innermost (deepest) frame first, as captured by the kernel.
+    pub stack: Vec<u64>,
+}
+
+impl StackWalkEvent {
+    const HEADER_LEN: usize = 8 + 4 + 4;
+
+    /// Parse a `StackWalk_Event`'s raw payload (its `UserData` buffer).
+    ///
+    /// Layout: `EventTimeStamp: i64`, `StackProcess: u32`, `StackThread: u32`, followed by a
+    /// variable-length array of `u64` return addresses filling the rest of the buffer.
+    pub(crate) fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::HEADER_LEN {
+            return None;
+        }
+
+        let event_timestamp = i64::from_ne_bytes(buf[0..8].try_into().ok()?);
+        let stack_process = u32::from_ne_bytes(buf[8..12].try_into().ok()?);
+        let stack_thread = u32::from_ne_bytes(buf[12..16].try_into().ok()?);
+
+        let stack = buf[Self::HEADER_LEN..]
+            .chunks_exact(8)
+            .map(|chunk| u64::from_ne_bytes(chunk.try_into().expect("chunks_exact(8)")))
+            .collect();
+
+        Some(Self {
+            event_timestamp: FILETIME {
+                dwLowDateTime: (event_timestamp & 0xFFFF_FFFF) as u32,
+                dwHighDateTime: (event_timestamp >> 32) as u32,
+            },
+            stack_process,
+            stack_thread,
+            stack,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synthetic_buffer(timestamp: i64, pid: u32, tid: u32, stack: &[u64]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&timestamp.to_ne_bytes());
+        buf.extend_from_slice(&pid.to_ne_bytes());
+        buf.extend_from_slice(&tid.to_ne_bytes());
+        for frame in stack {
+            buf.extend_from_slice(&frame.to_ne_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn parses_timestamp_pid_tid_and_stack() {
+        let buf = synthetic_buffer(0x0123_4567_89AB_CDEF, 1234, 5678, &[0xDEAD_BEEF, 0xCAFE_F00D]);
+
+        let event = StackWalkEvent::parse(&buf).expect("well-formed buffer should parse");
+
+        assert_eq!(event.event_timestamp.dwLowDateTime, 0x89AB_CDEF);
+        assert_eq!(event.event_timestamp.dwHighDateTime, 0x0123_4567);
+        assert_eq!(event.stack_process, 1234);
+        assert_eq!(event.stack_thread, 5678);
+        assert_eq!(event.stack, vec![0xDEAD_BEEF, 0xCAFE_F00D]);
+    }
+
+    #[test]
+    fn empty_stack_is_fine() {
+        let buf = synthetic_buffer(0, 1, 2, &[]);
+
+        let event = StackWalkEvent::parse(&buf).expect("header-only buffer should parse");
+
+        assert!(event.stack.is_empty());
+    }
+
+    #[test]
+    fn trailing_partial_frame_is_silently_truncated() {
+        let mut buf = synthetic_buffer(0, 1, 2, &[0x1122_3344_5566_7788]);
+        // A trailing, incomplete frame: `chunks_exact(8)` must drop it rather than error out.
+        buf.extend_from_slice(&[0xAA, 0xBB, 0xCC]);
+
+        let event = StackWalkEvent::parse(&buf).expect("buffer with a trailing partial frame should still parse");
+
+        assert_eq!(event.stack, vec![0x1122_3344_5566_7788]);
+    }
+
+    #[test]
+    fn buffer_shorter_than_header_does_not_parse() {
+        let buf = vec![0u8; StackWalkEvent::HEADER_LEN - 1];
+
+        assert!(StackWalkEvent::parse(&buf).is_none());
+    }
+}
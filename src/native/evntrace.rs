@@ -2,7 +2,7 @@
 //!
 //! This module makes sure the calls are safe memory-wise, but does not attempt to ensure they are called in the right order.<br/>
 //! Thus, you should prefer using `UserTrace`s, `KernelTrace`s and `TraceBuilder`s, that will ensure these API are correctly used.
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::ffi::c_void;
 use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
@@ -32,7 +32,6 @@ pub type TraceHandle = Etw::PROCESSTRACE_HANDLE;
 pub type ControlHandle = Etw::CONTROLTRACE_HANDLE;
 
 /// Evntrace native module errors
-#[derive(Debug)]
 pub enum EvntraceNativeError {
     /// Represents an Invalid Handle Error
     InvalidHandle,
@@ -40,6 +39,35 @@ pub enum EvntraceNativeError {
     AlreadyExist,
     /// Represents an standard IO Error
     IoError(std::io::Error),
+    /// A privilege (e.g. `SeSystemProfilePrivilege`) could not be enabled on the process token
+    /// because the token does not hold it at all (`ERROR_NOT_ALL_ASSIGNED`).
+    ///
+    /// This is distinct from [`EvntraceNativeError::IoError`] because `AdjustTokenPrivileges`
+    /// reports this case through `GetLastError` rather than through its own return value: it
+    /// returns success even when the privilege was not actually enabled.
+    PrivilegeNotHeld,
+    /// The user callback of this trace panicked while processing an event.
+    ///
+    /// The panic was caught so it would not unwind across the FFI boundary (which would be
+    /// undefined behaviour); the trace was then forcibly closed so that `process_trace`/
+    /// `process_traces` would actually return and resurface it here, rather than leaving the
+    /// trace silently dropping further events forever. The caller is expected to
+    /// `std::panic::resume_unwind` this payload, or otherwise report it, rather than silently
+    /// swallowing it.
+    CallbackPanicked(Box<dyn std::any::Any + Send>),
+}
+
+impl std::fmt::Debug for EvntraceNativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidHandle => write!(f, "InvalidHandle"),
+            Self::AlreadyExist => write!(f, "AlreadyExist"),
+            Self::IoError(e) => f.debug_tuple("IoError").field(e).finish(),
+            Self::PrivilegeNotHeld => write!(f, "PrivilegeNotHeld"),
+            // The panic payload (`Box<dyn Any + Send>`) does not implement `Debug`.
+            Self::CallbackPanicked(_) => write!(f, "CallbackPanicked(..)"),
+        }
+    }
 }
 
 pub(crate) type EvntraceNativeResult<T> = Result<T, EvntraceNativeError>;
@@ -60,69 +88,135 @@ pub(crate) type EvntraceNativeResult<T> = Result<T, EvntraceNativeError>;
 ///       Maybe also setting the BufferCallback in EVENT_TRACE_LOGFILEW may help us.
 ///       That's <https://github.com/n4r1b/ferrisetw/issues/62>
 static UNIQUE_VALID_CONTEXTS: UniqueValidContexts = UniqueValidContexts::new();
-struct UniqueValidContexts(Lazy<Mutex<HashSet<u64>>>);
+/// Maps a `CallbackData`'s context pointer to the `TraceHandle` it was opened with.
+///
+/// The handle is only known once `OpenTraceW` has returned, so a context is first [`reserve`]d
+/// (so no callback is considered valid for it before we've decided to track it), then its handle
+/// is filled in with [`set_handle`] once available.
+///
+/// [`reserve`]: UniqueValidContexts::reserve
+/// [`set_handle`]: UniqueValidContexts::set_handle
+struct UniqueValidContexts(Lazy<Mutex<HashMap<u64, Option<TraceHandle>>>>);
 enum ContextError {
     AlreadyExist,
 }
 
 impl UniqueValidContexts {
     pub const fn new() -> Self {
-        Self(Lazy::new(|| Mutex::new(HashSet::new())))
+        Self(Lazy::new(|| Mutex::new(HashMap::new())))
     }
-    /// Insert if it did not exist previously
-    fn insert(&self, ctx_ptr: *const c_void) -> Result<(), ContextError> {
-        match self.0.lock().unwrap().insert(ctx_ptr as u64) {
-            true => Ok(()),
-            false => Err(ContextError::AlreadyExist),
+
+    /// Reserve a slot for `ctx_ptr`, if it did not exist previously. The matching handle is not
+    /// known yet (see [`set_handle`](Self::set_handle)).
+    fn reserve(&self, ctx_ptr: *const c_void) -> Result<(), ContextError> {
+        use std::collections::hash_map::Entry;
+
+        match self.0.lock().unwrap().entry(ctx_ptr as u64) {
+            Entry::Occupied(_) => Err(ContextError::AlreadyExist),
+            Entry::Vacant(v) => {
+                v.insert(None);
+                Ok(())
+            }
         }
     }
 
+    /// Record the trace handle matching a previously [`reserve`](Self::reserve)d context.
+    fn set_handle(&self, ctx_ptr: *const c_void, handle: TraceHandle) {
+        self.0.lock().unwrap().insert(ctx_ptr as u64, Some(handle));
+    }
+
     fn remove(&self, ctx_ptr: *const c_void) {
         self.0.lock().unwrap().remove(&(ctx_ptr as u64));
     }
 
     pub fn is_valid(&self, ctx_ptr: *const c_void) -> bool {
-        self.0.lock().unwrap().contains(&(ctx_ptr as u64))
+        self.0.lock().unwrap().contains_key(&(ctx_ptr as u64))
+    }
+
+    /// The trace handle tracked for `ctx_ptr`, if any (it may not be known yet if `OpenTraceW`
+    /// hasn't returned yet).
+    fn handle_for(&self, ctx_ptr: *const c_void) -> Option<TraceHandle> {
+        self.0.lock().unwrap().get(&(ctx_ptr as u64)).copied().flatten()
     }
 }
 
+/// Panics caught from a trace's callback, keyed by the `TraceHandle` of the trace that panicked,
+/// waiting to be resurfaced by [`process_trace`]/[`process_traces`] via [`take_callback_panic`].
+static CAUGHT_PANICS: Lazy<Mutex<HashMap<u64, Box<dyn std::any::Any + Send>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Take back a panic previously caught from `trace_handle`'s callback, if any.
+///
+/// Called by [`process_trace`]/[`process_traces`] once `ProcessTrace` has returned, since
+/// `trace_callback_thunk` forces that handle closed as soon as it stashes a panic here (so this
+/// is never left stranded waiting for a call that would otherwise never come).
+fn take_callback_panic(trace_handle: TraceHandle) -> Option<Box<dyn std::any::Any + Send>> {
+    CAUGHT_PANICS.lock().unwrap().remove(&trace_handle.Value)
+}
+
 /// This will be called by the ETW framework whenever an ETW event is available
 extern "system" fn trace_callback_thunk(p_record: *mut Etw::EVENT_RECORD) {
-    match std::panic::catch_unwind(AssertUnwindSafe(|| {
-        let record_from_ptr = unsafe {
-            // Safety: lifetime is valid at least until the end of the callback. A correct lifetime will be attached when we pass the reference to the child function
-            EventRecord::from_ptr(p_record)
-        };
+    let record_from_ptr = unsafe {
+        // Safety: lifetime is valid at least until the end of the callback. A correct lifetime will be attached when we pass the reference to the child function
+        EventRecord::from_ptr(p_record)
+    };
 
-        if let Some(event_record) = record_from_ptr {
-            let p_user_context = event_record.user_context();
-            if !UNIQUE_VALID_CONTEXTS.is_valid(p_user_context) {
-                return;
-            }
-            let p_callback_data = p_user_context.cast::<Arc<CallbackData>>();
-            let callback_data = unsafe {
-                // Safety:
-                //  * the API of this create guarantees this points to a `CallbackData` already allocated and created
-                //  * we've just checked using UNIQUE_VALID_CONTEXTS that this `CallbackData` has not been dropped
-                //  * the API of this crate guarantees this `CallbackData` is not mutated from another thread during the trace:
-                //      * we're the only one to change CallbackData::events_handled (and that's an atomic, so it's fine)
-                //      * the list of Providers is a constant (may change in the future with #54)
-                //      * the schema_locator only has interior mutability
-                p_callback_data.as_ref()
-            };
-            if let Some(callback_data) = callback_data {
-                // The UserContext is owned by the `Trace` object. When it is dropped, so will the UserContext.
-                // We clone it now, so that the original Arc can be safely dropped at all times, but the callback data (including the closure captured context) will still be alive until the callback ends.
-                let cloned_arc = Arc::clone(callback_data);
-                cloned_arc.on_event(event_record);
-            }
+    let Some(event_record) = record_from_ptr else {
+        return;
+    };
+
+    let p_user_context = event_record.user_context();
+    if !UNIQUE_VALID_CONTEXTS.is_valid(p_user_context) {
+        return;
+    }
+
+    let panic_result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let p_callback_data = p_user_context.cast::<Arc<CallbackData>>();
+        let callback_data = unsafe {
+            // Safety:
+            //  * the API of this create guarantees this points to a `CallbackData` already allocated and created
+            //  * we've just checked using UNIQUE_VALID_CONTEXTS that this `CallbackData` has not been dropped
+            //  * the API of this crate guarantees this `CallbackData` is not mutated from another thread during the trace:
+            //      * we're the only one to change CallbackData::events_handled (and that's an atomic, so it's fine)
+            //      * the list of Providers is a constant (may change in the future with #54)
+            //      * the schema_locator only has interior mutability
+            p_callback_data.as_ref()
+        };
+        if let Some(callback_data) = callback_data {
+            // The UserContext is owned by the `Trace` object. When it is dropped, so will the UserContext.
+            // We clone it now, so that the original Arc can be safely dropped at all times, but the callback data (including the closure captured context) will still be alive until the callback ends.
+            let cloned_arc = Arc::clone(callback_data);
+            cloned_arc.on_event(event_record);
         }
-    })) {
-        Ok(_) => {}
-        Err(e) => {
-            log::error!("UNIMPLEMENTED PANIC: {e:?}");
-            std::process::exit(1);
+    }));
+
+    if let Err(panic_payload) = panic_result {
+        // The thunk must never unwind across the FFI boundary (undefined behaviour), so the panic
+        // was caught above. Rather than aborting the whole process -- which may be running
+        // unrelated work -- stash the payload for this trace, keyed by its `TraceHandle`.
+        if let Some(trace_handle) = UNIQUE_VALID_CONTEXTS.handle_for(p_user_context) {
+            CAUGHT_PANICS
+                .lock()
+                .unwrap()
+                .insert(trace_handle.Value, panic_payload);
+
+            // For a real-time session, nothing else would ever make `ProcessTrace` return: we've
+            // just stopped delivering events for this handle (see `UNIQUE_VALID_CONTEXTS.remove`
+            // below), but ETW itself doesn't know to stop. Request this trace to close so
+            // `ProcessTrace` actually returns and `process_trace`/`process_traces` can resurface
+            // the stashed panic via `take_callback_panic`, instead of hanging forever.
+            //
+            // This races with a caller that later calls `close_trace` on the same handle as part
+            // of its normal shutdown path (e.g. `Trace::drop`): `close_trace` checks
+            // `UNIQUE_VALID_CONTEXTS.is_valid` before calling `CloseTrace` again, so it treats the
+            // handle we're about to close here as already gone rather than double-closing it.
+            let _ = unsafe {
+                // Safety: `trace_handle` is a still-open handle (it is only removed from
+                // `UNIQUE_VALID_CONTEXTS` -- and never reused -- after this point).
+                Etw::CloseTrace(trace_handle)
+            };
         }
+        UNIQUE_VALID_CONTEXTS.remove(p_user_context);
     }
 }
 
@@ -149,13 +243,39 @@ fn filter_invalid_control_handle(h: ControlHandle) -> Option<ControlHandle> {
 
 /// Create a new session.
 ///
-/// This builds an `EventTraceProperties`, calls `StartTraceW` and returns the built `EventTraceProperties` as well as the trace ControlHandle
+/// This builds an `EventTraceProperties`, calls `StartTraceW` and returns the built `EventTraceProperties` as well as the trace ControlHandle.
+///
+/// This is a thin wrapper over [`start_trace_with_recovery`] with `StartMode::default()`
+/// (`StopStaleAndRetry`): a leaked session from a crashed or still-running previous instance of
+/// `trace_name` is automatically torn down and `StartTraceW` retried once, rather than failing
+/// with [`EvntraceNativeError::AlreadyExist`]. Call [`start_trace_with_recovery`] directly if you
+/// need to pick the mode explicitly (e.g. `StartMode::FailIfExists` to keep the old behaviour).
 pub(crate) fn start_trace<T>(
     trace_name: &U16CStr,
     etl_dump_file: Option<(&U16CStr, DumpFileLoggingMode, Option<u32>)>,
     trace_properties: &TraceProperties,
     enable_flags: Etw::EVENT_TRACE_FLAG,
 ) -> EvntraceNativeResult<(EventTraceProperties, ControlHandle)>
+where
+    T: RealTimeTraceTrait,
+{
+    start_trace_with_recovery::<T>(
+        trace_name,
+        etl_dump_file,
+        trace_properties,
+        enable_flags,
+        StartMode::default(),
+    )
+}
+
+/// The actual `StartTraceW` call, with no `ERROR_ALREADY_EXISTS` recovery: see [`start_trace`]/
+/// [`start_trace_with_recovery`], which are what everything else in this crate should call.
+fn start_trace_once<T>(
+    trace_name: &U16CStr,
+    etl_dump_file: Option<(&U16CStr, DumpFileLoggingMode, Option<u32>)>,
+    trace_properties: &TraceProperties,
+    enable_flags: Etw::EVENT_TRACE_FLAG,
+) -> EvntraceNativeResult<(EventTraceProperties, ControlHandle)>
 where
     T: RealTimeTraceTrait,
 {
@@ -195,6 +315,72 @@ where
     }
 }
 
+/// How [`start_trace_with_recovery`] should react to an `ERROR_ALREADY_EXISTS` from `StartTraceW`.
+///
+/// A session with the same name routinely survives a crash or a previous run of the same tool
+/// (it only goes away on reboot otherwise), so the default is to recover from it rather than fail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum StartMode {
+    /// Fail immediately with [`EvntraceNativeError::AlreadyExist`].
+    FailIfExists,
+    /// Stop the stale session (`ControlTraceW(..., EVENT_TRACE_CONTROL_STOP)`) and retry
+    /// `StartTraceW` once.
+    #[default]
+    StopStaleAndRetry,
+}
+
+/// Same as [`start_trace_once`], but reacts to `ERROR_ALREADY_EXISTS` according to `mode`.
+/// [`start_trace`] is a convenience wrapper calling this with `StartMode::default()`.
+///
+/// With [`StartMode::StopStaleAndRetry`], a leaked session from a crashed or still-running
+/// previous instance of `trace_name` is torn down and `StartTraceW` is retried once. If the retry
+/// still fails, the error from that retry is returned.
+pub(crate) fn start_trace_with_recovery<T>(
+    trace_name: &U16CStr,
+    etl_dump_file: Option<(&U16CStr, DumpFileLoggingMode, Option<u32>)>,
+    trace_properties: &TraceProperties,
+    enable_flags: Etw::EVENT_TRACE_FLAG,
+    mode: StartMode,
+) -> EvntraceNativeResult<(EventTraceProperties, ControlHandle)>
+where
+    T: RealTimeTraceTrait,
+{
+    let first_attempt =
+        start_trace_once::<T>(trace_name, etl_dump_file, trace_properties, enable_flags);
+
+    match (first_attempt, mode) {
+        (Err(EvntraceNativeError::AlreadyExist), StartMode::StopStaleAndRetry) => {
+            let mut stale_properties =
+                EventTraceProperties::new::<T>(trace_name, etl_dump_file, trace_properties, enable_flags);
+
+            // We don't have a handle to the stale session (it wasn't opened by us), so we have to
+            // address it by name.
+            control_trace_by_name(
+                &mut stale_properties,
+                trace_name,
+                Etw::EVENT_TRACE_CONTROL_STOP,
+            )?;
+
+            start_trace_once::<T>(trace_name, etl_dump_file, trace_properties, enable_flags)
+        }
+        (result, _) => result,
+    }
+}
+
+/// Append a short, unique token (current process id + a per-process counter) to `base_name`, so
+/// that concurrent tracers never collide on the same session name in the first place.
+///
+/// This is an alternative to [`StartMode::StopStaleAndRetry`]: useful when several instances of
+/// the same tool are expected to run side by side rather than one replacing the other.
+pub(crate) fn unique_trace_name(base_name: &str) -> String {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let pid = std::process::id();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{base_name}-{pid}-{count}")
+}
+
 /// Connect to an existing session.
 ///
 /// This queries an existing session by name and returns the EventTraceProperties and a special ControlHandle 
@@ -254,7 +440,7 @@ pub(crate) fn open_trace(
     let mut log_file =
         EventTraceLogfile::create(callback_data, subscription_source, trace_callback_thunk);
 
-    if let Err(ContextError::AlreadyExist) = UNIQUE_VALID_CONTEXTS.insert(log_file.context_ptr()) {
+    if let Err(ContextError::AlreadyExist) = UNIQUE_VALID_CONTEXTS.reserve(log_file.context_ptr()) {
         // That's probably possible to get multiple handles to the same trace, by opening them multiple times.
         // But that's left as a future TODO. Making things right and safe is difficult enough with a single opening of the trace already.
         return Err(EvntraceNativeError::AlreadyExist);
@@ -270,8 +456,12 @@ pub(crate) fn open_trace(
     };
 
     if filter_invalid_trace_handles(trace_handle).is_none() {
+        UNIQUE_VALID_CONTEXTS.remove(log_file.context_ptr());
         Err(EvntraceNativeError::IoError(std::io::Error::last_os_error()))
     } else {
+        // Now that we know the handle, record it so that `trace_callback_thunk` can key
+        // `CAUGHT_PANICS` by `TraceHandle` rather than by this context pointer.
+        UNIQUE_VALID_CONTEXTS.set_handle(log_file.context_ptr(), trace_handle);
         Ok(trace_handle)
     }
 }
@@ -320,23 +510,73 @@ pub(crate) fn enable_provider(
 /// Start processing a trace (this call is blocking until the trace is stopped)
 ///
 /// You probably want to spawn a thread that will block on this call.
+///
+/// If the callback panicked while this trace was being processed, `trace_callback_thunk` closes
+/// the trace so that this call actually returns, and the panic is surfaced here as
+/// [`EvntraceNativeError::CallbackPanicked`] -- the caller is expected to
+/// `std::panic::resume_unwind` it (or otherwise report it) rather than silently swallowing it.
 pub(crate) fn process_trace(trace_handle: TraceHandle) -> EvntraceNativeResult<()> {
-    if filter_invalid_trace_handles(trace_handle).is_none() {
-        Err(EvntraceNativeError::InvalidHandle)
-    } else {
-        let result = unsafe {
-            // We want to start processing events as soon as January 1601.
-            // * for ETL file traces, this is fine, this means "process everything from the file"
-            // * for real-time traces, this means we might process a few events already waiting in the buffers when the processing is starting. This is fine, I suppose.
-            let mut start = FILETIME::default();
-            Etw::ProcessTrace(&[trace_handle], Some(&mut start as *mut FILETIME), None)
-        }
-        .ok();
+    process_traces(&[trace_handle])
+}
+
+/// Start processing several traces on the calling thread with a single `ProcessTrace` call (this
+/// call is blocking until every trace is stopped).
+///
+/// This merges the buffered events of all `trace_handles` into a single, timestamp-ordered
+/// stream delivered to the calling thread -- e.g. a real-time user session and a kernel session,
+/// or several ETL files, can be processed together this way. As with [`process_trace`], you
+/// probably want to spawn a thread that will block on this call.
+///
+/// Each handle's `CallbackData` is tracked (via [`UNIQUE_VALID_CONTEXTS`]) and can be freed
+/// (via [`close_trace`]) independently of the others: stopping one trace does not require
+/// stopping the rest.
+///
+/// # Note
+/// No public, multi-trace-aware builder exists in this crate yet (mirroring `UserTrace`/
+/// `KernelTrace` to merge e.g. a real-time user session and a kernel session onto one thread) --
+/// only [`process_trace`] calls this, with a single-element slice. This native plumbing is exposed
+/// for such a builder to call once it exists; see the `trace_handles.len() > 1` tests below for
+/// the multi-handle path it would exercise.
+///
+/// # Notes
+/// If any single trace's callback panics, `trace_callback_thunk` closes that trace to force this
+/// call to return (see [`EvntraceNativeError::CallbackPanicked`]); Windows does not document
+/// whether this lets the other, still-open traces keep being processed by the same `ProcessTrace`
+/// call, so callers should treat the whole call as stopped and re-open/re-process the surviving
+/// handles if needed.
+///
+/// TODO: `ProcessTrace` requires all traces to share the same clock resolution
+/// (`Wnode.ClientContext`); validate that here before calling it, so mixing incompatible clocks is
+/// rejected with a clear error from this function instead of an opaque `ERROR_*` from
+/// `ProcessTrace` itself. Doing so needs each handle's originating `EventTraceProperties`, which
+/// this function does not currently take.
+pub(crate) fn process_traces(trace_handles: &[TraceHandle]) -> EvntraceNativeResult<()> {
+    if trace_handles.is_empty() || trace_handles.iter().any(|h| filter_invalid_trace_handles(*h).is_none()) {
+        return Err(EvntraceNativeError::InvalidHandle);
+    }
+
+    let result = unsafe {
+        // We want to start processing events as soon as January 1601.
+        // * for ETL file traces, this is fine, this means "process everything from the file"
+        // * for real-time traces, this means we might process a few events already waiting in the buffers when the processing is starting. This is fine, I suppose.
+        let mut start = FILETIME::default();
+        Etw::ProcessTrace(trace_handles, Some(&mut start as *mut FILETIME), None)
+    }
+    .ok();
 
-        result.map_err(|err| {
-            EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
-        })
+    // If one of these traces' callbacks panicked, `trace_callback_thunk` closed it to make
+    // `ProcessTrace` return (rather than hang forever) and stashed the panic here: resurface it
+    // in preference to whatever I/O error `ProcessTrace` reports for the handle it did not expect
+    // to see closed out from under it.
+    for &trace_handle in trace_handles {
+        if let Some(panic_payload) = take_callback_panic(trace_handle) {
+            return Err(EvntraceNativeError::CallbackPanicked(panic_payload));
+        }
     }
+
+    result.map_err(|err| {
+        EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
+    })
 }
 
 /// Call `ControlTraceW` on the trace
@@ -413,9 +653,17 @@ pub(crate) fn close_trace(
     match filter_invalid_trace_handles(trace_handle) {
         None => Err(EvntraceNativeError::InvalidHandle),
         Some(handle) => {
+            let ctx_ptr = callback_data.as_ref() as *const Arc<CallbackData> as *const c_void;
+
+            if !UNIQUE_VALID_CONTEXTS.is_valid(ctx_ptr) {
+                // `trace_callback_thunk` already force-closed this handle itself, after a panic
+                // caught from this trace's callback (see there): it is already gone, which is the
+                // clean "trace is done" state this function is meant to produce, not an error.
+                return Ok(false);
+            }
+
             // By contruction, only one Provider used this context in its callback. It is safe to remove it, it won't be used by anyone else.
-            UNIQUE_VALID_CONTEXTS
-                .remove(callback_data.as_ref() as *const Arc<CallbackData> as *const c_void);
+            UNIQUE_VALID_CONTEXTS.remove(ctx_ptr);
 
             let status = unsafe { Etw::CloseTrace(handle) }.ok();
 
@@ -447,3 +695,174 @@ pub(crate) fn query_info(class: TraceInformation, buf: &mut [u8]) -> EvntraceNat
         EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
     })
 }
+
+/// Sets system-wide or session-wide ETW information (the write-counterpart of [`query_info`]).
+pub(crate) fn set_trace_information(
+    control_handle: ControlHandle,
+    class: Etw::TRACE_INFO_CLASS,
+    buf: &[u8],
+) -> EvntraceNativeResult<()> {
+    let result = unsafe {
+        // Safety:
+        //  * `control_handle` is a still-valid handle to a started trace
+        //  * `buf` is a valid, borrowed buffer, only read for the duration of this call
+        Etw::TraceSetInformation(control_handle, class, buf.as_ptr().cast(), buf.len() as u32)
+    }
+    .ok();
+
+    result.map_err(|err| {
+        EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
+    })
+}
+
+/// Start a kernel session already configured for stack-walk capture and/or CPU sampling.
+///
+/// This is the minimal kernel-session entry point for [`enable_stack_walk`]/
+/// [`set_sampled_profile_interval`]: it starts the trace (see [`start_trace_with_recovery`]) and
+/// then applies whichever of the two is requested, before returning the `ControlHandle` to the
+/// caller, so a kernel-session consumer does not have to sequence these calls itself. Pass an
+/// empty `stack_walk_events` and/or `sampled_profile_interval_100ns: None` to skip either one.
+/// If `enable_flags` includes `EVENT_TRACE_FLAG_PROFILE`, `SeSystemProfilePrivilege` is enabled
+/// before the trace is started.
+///
+/// A fuller, `TraceBuilder`-integrated API for this (mirroring `UserTrace`/`KernelTrace`) is still
+/// future work; this gives kernel-session callers within the crate a single call to reach for in
+/// the meantime, rather than leaving [`enable_stack_walk`]/[`set_sampled_profile_interval`] unused.
+pub(crate) fn start_kernel_profiling_trace<T>(
+    trace_name: &U16CStr,
+    etl_dump_file: Option<(&U16CStr, DumpFileLoggingMode, Option<u32>)>,
+    trace_properties: &TraceProperties,
+    enable_flags: Etw::EVENT_TRACE_FLAG,
+    mode: StartMode,
+    stack_walk_events: &[ClassicEventId],
+    sampled_profile_interval_100ns: Option<u32>,
+) -> EvntraceNativeResult<(EventTraceProperties, ControlHandle)>
+where
+    T: RealTimeTraceTrait,
+{
+    // `StartTraceW` itself needs the privilege when CPU sampling is requested through `enable_flags`.
+    if enable_flags.0 & Etw::EVENT_TRACE_FLAG_PROFILE.0 != 0 {
+        super::privilege::enable_system_profile_privilege()?;
+    }
+
+    let (properties, control_handle) =
+        start_trace_with_recovery::<T>(trace_name, etl_dump_file, trace_properties, enable_flags, mode)?;
+
+    if !stack_walk_events.is_empty() {
+        enable_stack_walk(control_handle, stack_walk_events)?;
+    }
+
+    if let Some(interval) = sampled_profile_interval_100ns {
+        set_sampled_profile_interval(control_handle, interval)?;
+    }
+
+    Ok((properties, control_handle))
+}
+
+/// One entry of the array passed to `TraceSetInformation(..., TraceStackTracingInfo, ...)`:
+/// requests that a kernel stack be captured whenever `event_guid` emits an event whose
+/// type/opcode is `event_type`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClassicEventId {
+    pub event_guid: GUID,
+    pub event_type: u8,
+    _reserved: [u8; 7],
+}
+
+impl ClassicEventId {
+    pub(crate) fn new(event_guid: GUID, event_type: u8) -> Self {
+        Self {
+            event_guid,
+            event_type,
+            _reserved: [0; 7],
+        }
+    }
+}
+
+/// Enable kernel stack-walk capture for the given (provider, event type) pairs, so that a
+/// `StackWalk_Event` (see [`crate::native::etw_types::stack_walk::StackWalkEvent`]) is emitted
+/// alongside every matching event.
+///
+/// Requires a kernel session. This enables `SeSystemProfilePrivilege` on the current process's
+/// token itself (see [`super::privilege::enable_system_profile_privilege`]), since that privilege
+/// is off by default and every caller of this function would need it anyway.
+///
+/// Called by [`start_kernel_profiling_trace`], which is the minimal kernel-session entry point
+/// that drives this; a fuller `TraceBuilder`-integrated API is still future work.
+pub(crate) fn enable_stack_walk(
+    control_handle: ControlHandle,
+    events: &[ClassicEventId],
+) -> EvntraceNativeResult<()> {
+    super::privilege::enable_system_profile_privilege()?;
+
+    let bytes = unsafe {
+        // Safety: `ClassicEventId` is `#[repr(C)]` and POD, so reinterpreting the slice as bytes
+        // is valid; the resulting slice does not outlive `events`.
+        std::slice::from_raw_parts(events.as_ptr().cast::<u8>(), std::mem::size_of_val(events))
+    };
+
+    set_trace_information(control_handle, Etw::TraceStackTracingInfo, bytes)
+}
+
+/// Set the sampling period for `EVENT_TRACE_FLAG_PROFILE`-driven CPU sampling, in 100ns units
+/// (e.g. `10_000` for a ~1ms period).
+///
+/// Like [`enable_stack_walk`], this enables `SeSystemProfilePrivilege` itself, and is likewise
+/// driven by [`start_kernel_profiling_trace`].
+pub(crate) fn set_sampled_profile_interval(
+    control_handle: ControlHandle,
+    interval_100ns: u32,
+) -> EvntraceNativeResult<()> {
+    super::privilege::enable_system_profile_privilege()?;
+
+    let profile_interval = Etw::TRACE_PROFILE_INTERVAL {
+        Interval: interval_100ns,
+        ..Default::default()
+    };
+
+    let bytes = unsafe {
+        // Safety: `TRACE_PROFILE_INTERVAL` is POD; the resulting slice does not outlive `profile_interval`.
+        std::slice::from_raw_parts(
+            (&profile_interval as *const Etw::TRACE_PROFILE_INTERVAL).cast::<u8>(),
+            std::mem::size_of::<Etw::TRACE_PROFILE_INTERVAL>(),
+        )
+    };
+
+    set_trace_information(control_handle, Etw::TraceSampledProfileIntervalInfo, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(value: u64) -> TraceHandle {
+        TraceHandle { Value: value }
+    }
+
+    #[test]
+    fn filter_invalid_trace_handles_rejects_the_documented_sentinels() {
+        assert!(filter_invalid_trace_handles(handle(0)).is_some());
+        assert!(filter_invalid_trace_handles(handle(123)).is_some());
+        assert!(filter_invalid_trace_handles(handle(u32::MAX as u64)).is_none());
+        assert!(filter_invalid_trace_handles(handle(u64::MAX)).is_none());
+    }
+
+    #[test]
+    fn process_traces_rejects_an_empty_slice() {
+        assert!(matches!(process_traces(&[]), Err(EvntraceNativeError::InvalidHandle)));
+    }
+
+    #[test]
+    fn process_traces_rejects_if_any_of_several_handles_is_invalid() {
+        // This is the `trace_handles.len() > 1` path `process_traces`'s docs note has no real
+        // caller for yet: a valid-looking handle alongside one of the documented invalid
+        // sentinels must still be rejected up front, before `ProcessTrace` is ever called.
+        let handles = [handle(123), handle(u64::MAX)];
+
+        assert!(matches!(
+            process_traces(&handles),
+            Err(EvntraceNativeError::InvalidHandle)
+        ));
+    }
+}
@@ -0,0 +1,188 @@
+//! Safe wrappers for the native ETW *event-writing* API
+//!
+//! This is the write-side counterpart of [`evntrace`](super::evntrace): instead of consuming
+//! events produced by other processes, it lets the current process register itself as an ETW
+//! provider (`EventRegister`) and emit events (`EventWrite`/`EventWriteEx`), so a process can
+//! write events in one place and consume them with a `UserTrace` somewhere else.
+use std::ffi::c_void;
+use std::panic::AssertUnwindSafe;
+
+use windows::core::GUID;
+use windows::Win32::Foundation::ERROR_SUCCESS;
+use windows::Win32::System::Diagnostics::Etw;
+use windows::Win32::System::Diagnostics::Etw::EVENT_DATA_DESCRIPTOR;
+use windows::Win32::System::Diagnostics::Etw::EVENT_DESCRIPTOR;
+use windows::Win32::System::Diagnostics::Etw::EVENT_FILTER_DESCRIPTOR;
+
+use super::evntrace::EvntraceNativeError;
+
+pub(crate) type EvntraceNativeResult<T> = Result<T, EvntraceNativeError>;
+
+/// A registration handle returned by `EventRegister`, identifying this process as a provider.
+pub(crate) type RegHandle = u64;
+
+/// Register the current process as an ETW provider identified by `provider_guid`.
+///
+/// `enable_callback` is invoked by ETW (on an arbitrary thread) whenever a controlling session
+/// enables/disables this provider or changes its level/keyword filter; `callback_context` is
+/// handed back to it unchanged. Pass `None` if the provider does not need to react to that.
+///
+/// # Safety
+/// `callback_context` must stay valid (i.e. not be freed or moved) for as long as the provider
+/// stays registered, since ETW may invoke `enable_callback` with it at any point until
+/// [`event_unregister`] returns.
+pub(crate) unsafe fn event_register(
+    provider_guid: &GUID,
+    enable_callback: Etw::PENABLECALLBACK,
+    callback_context: *const c_void,
+) -> EvntraceNativeResult<RegHandle> {
+    let mut handle: u64 = 0;
+
+    let status = unsafe {
+        // Safety:
+        //  * `provider_guid` is a valid, borrowed GUID for the duration of this call
+        //  * `handle` is a valid, allocated output address
+        //  * the caller guarantees `callback_context` stays valid for as long as required (see above)
+        Etw::EventRegister(
+            provider_guid as *const GUID,
+            enable_callback,
+            Some(callback_context),
+            &mut handle,
+        )
+    };
+
+    if status != ERROR_SUCCESS.0 {
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(status as i32),
+        ));
+    }
+
+    Ok(handle)
+}
+
+/// Unregister a provider previously registered with [`event_register`].
+///
+/// After this returns, ETW guarantees no further `enable_callback` invocation is in flight.
+pub(crate) fn event_unregister(handle: RegHandle) -> EvntraceNativeResult<()> {
+    let status = unsafe {
+        // Safety: `handle` was returned by a previous, still-valid call to `EventRegister`.
+        Etw::EventUnregister(handle)
+    };
+
+    if status != ERROR_SUCCESS.0 {
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(status as i32),
+        ));
+    }
+
+    Ok(())
+}
+
+/// This will be called by ETW whenever a controlling session enables/disables this provider, or
+/// changes its level/keyword filter (see `EnableTraceEx2` and [`super::evntrace::enable_provider`]
+/// on the consumer side).
+///
+/// `callback_context` is the pointer handed to [`event_register`], i.e. a
+/// `*const ProviderContext` (see [`crate::provider::writer`]). The thunk must never unwind across
+/// the FFI boundary, so a panicking `EnableCallback` closure is caught and logged here rather than
+/// propagated.
+pub(crate) extern "system" fn enable_callback_thunk(
+    _source_id: *const GUID,
+    control_code: u32,
+    level: u8,
+    match_any_keyword: u64,
+    match_all_keyword: u64,
+    _filter_data: *mut EVENT_FILTER_DESCRIPTOR,
+    callback_context: *mut c_void,
+) {
+    if let Err(e) = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // Safety: `callback_context` was set to `Arc::as_ptr(&context).cast()` by
+        // `EtwProvider::register`/`register_with_callback`, and that `Arc` is kept alive in
+        // `EtwProvider::context` for as long as the provider stays registered.
+        crate::provider::writer::dispatch_enable_callback(
+            callback_context.cast_const(),
+            control_code,
+            level,
+            match_any_keyword,
+            match_all_keyword,
+        );
+    })) {
+        let message = e
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| e.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("<non-string panic payload>");
+        log::error!("ETW EnableCallback panicked: {message}");
+    }
+}
+
+/// Write a single event, described by `event_descriptor`, with `data` as its payload fields.
+///
+/// `data` must be built from the event's fields, in the order a consumer's manifest/TraceLogging
+/// layout expects them (see [`EventDataField`](crate::provider::writer::field::EventDataField)).
+pub(crate) fn event_write(
+    handle: RegHandle,
+    event_descriptor: &EVENT_DESCRIPTOR,
+    data: &[EVENT_DATA_DESCRIPTOR],
+) -> EvntraceNativeResult<()> {
+    let user_data = if data.is_empty() { None } else { Some(data) };
+
+    let status = unsafe {
+        // Safety:
+        //  * `handle` was returned by a previous, still-valid call to `EventRegister`
+        //  * `event_descriptor` is a valid, borrowed `EVENT_DESCRIPTOR`
+        //  * each `EVENT_DATA_DESCRIPTOR` in `data` points to memory that outlives this call
+        //    (the caller, i.e. `EtwProvider::write`, builds them from still-alive `EventDataField`s)
+        Etw::EventWrite(handle, event_descriptor as *const EVENT_DESCRIPTOR, user_data)
+    };
+
+    if status != ERROR_SUCCESS.0 {
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(status as i32),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Same as [`event_write`], but additionally stamping the event with an `ActivityId` and/or a
+/// `RelatedActivityId`, so consumers can correlate it with other events from the same logical
+/// operation (`activity_id`), or link it back to the operation that spawned it
+/// (`related_activity_id`). Pass `None` for either to omit it.
+pub(crate) fn event_write_ex(
+    handle: RegHandle,
+    event_descriptor: &EVENT_DESCRIPTOR,
+    activity_id: Option<&GUID>,
+    related_activity_id: Option<&GUID>,
+    data: &[EVENT_DATA_DESCRIPTOR],
+) -> EvntraceNativeResult<()> {
+    let user_data = if data.is_empty() { None } else { Some(data) };
+
+    let status = unsafe {
+        // Safety:
+        //  * `handle` was returned by a previous, still-valid call to `EventRegister`
+        //  * `event_descriptor` is a valid, borrowed `EVENT_DESCRIPTOR`
+        //  * `activity_id`/`related_activity_id`, if present, are valid, borrowed GUIDs for the
+        //    duration of this call
+        //  * each `EVENT_DATA_DESCRIPTOR` in `data` points to memory that outlives this call
+        //    (the caller, i.e. `EtwProvider::write_with_activity`, builds them from still-alive
+        //    `EventDataField`s)
+        Etw::EventWriteEx(
+            handle,
+            event_descriptor as *const EVENT_DESCRIPTOR,
+            0,
+            0,
+            activity_id.map(|guid| guid as *const GUID),
+            related_activity_id.map(|guid| guid as *const GUID),
+            user_data,
+        )
+    };
+
+    if status != ERROR_SUCCESS.0 {
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(status as i32),
+        ));
+    }
+
+    Ok(())
+}
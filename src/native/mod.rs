@@ -0,0 +1,4 @@
+pub(crate) mod etw_types;
+pub(crate) mod evntrace;
+pub(crate) mod evntrace_write;
+pub(crate) mod privilege;
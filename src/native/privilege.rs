@@ -0,0 +1,80 @@
+//! Helpers to adjust the current process token's privileges.
+//!
+//! Kernel features like CPU sampling (`EVENT_TRACE_FLAG_PROFILE`) and stack-walk capture require
+//! the calling process to hold `SeSystemProfilePrivilege`, which is disabled by default even for
+//! processes running as administrator.
+use windows::Win32::Foundation::{CloseHandle, GetLastError, ERROR_NOT_ALL_ASSIGNED, HANDLE, LUID};
+use windows::Win32::Security::{
+    AdjustTokenPrivileges, LookupPrivilegeValueW, LUID_AND_ATTRIBUTES, SE_PRIVILEGE_ENABLED,
+    TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES, TOKEN_QUERY,
+};
+use windows::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+use windows::core::{w, PCWSTR};
+
+use super::evntrace::EvntraceNativeError;
+
+/// Enable `SeSystemProfilePrivilege` on the current process's token.
+///
+/// This is required before a kernel session can be configured for CPU sampling
+/// (see [`super::evntrace::set_sampled_profile_interval`]) or stack-walk capture
+/// (see [`super::evntrace::enable_stack_walk`]).
+pub(crate) fn enable_system_profile_privilege() -> Result<(), EvntraceNativeError> {
+    enable_privilege(w!("SeSystemProfilePrivilege"))
+}
+
+fn enable_privilege(privilege_name: PCWSTR) -> Result<(), EvntraceNativeError> {
+    let mut token = HANDLE::default();
+
+    unsafe {
+        // Safety: `token` is a valid, allocated output address.
+        OpenProcessToken(
+            GetCurrentProcess(),
+            TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY,
+            &mut token,
+        )
+    }
+    .map_err(|e| EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(e.code().0)))?;
+
+    let mut luid = LUID::default();
+    let lookup_result = unsafe {
+        // Safety: `privilege_name` is a valid, null-terminated widestring; `luid` is a valid output address.
+        LookupPrivilegeValueW(PCWSTR::null(), privilege_name, &mut luid)
+    };
+
+    if let Err(e) = lookup_result {
+        let _ = unsafe { CloseHandle(token) };
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(e.code().0),
+        ));
+    }
+
+    let privileges = TOKEN_PRIVILEGES {
+        PrivilegeCount: 1,
+        Privileges: [LUID_AND_ATTRIBUTES {
+            Luid: luid,
+            Attributes: SE_PRIVILEGE_ENABLED,
+        }],
+    };
+
+    let adjust_result = unsafe {
+        // Safety: `token` is a still-valid, just-opened token handle; `privileges` is a valid, borrowed buffer.
+        AdjustTokenPrivileges(token, false, Some(&privileges), 0, None, None)
+    };
+
+    // `AdjustTokenPrivileges` returns success even when it silently fails to assign a privilege
+    // (e.g. the token doesn't hold it at all): that case only shows up via `GetLastError` being
+    // `ERROR_NOT_ALL_ASSIGNED`, so it must be checked right after the call, before anything else
+    // (including `CloseHandle`) can overwrite the last-error value.
+    let not_all_assigned = adjust_result.is_ok() && unsafe { GetLastError() } == ERROR_NOT_ALL_ASSIGNED;
+
+    let _ = unsafe { CloseHandle(token) };
+
+    adjust_result
+        .map_err(|e| EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(e.code().0)))?;
+
+    if not_all_assigned {
+        return Err(EvntraceNativeError::PrivilegeNotHeld);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Typed fields written into an event's `EVENT_DATA_DESCRIPTOR` array.
+use widestring::U16CString;
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::EVENT_DATA_DESCRIPTOR;
+
+/// A single typed field of an event payload.
+///
+/// Each variant owns the bytes it serializes to, so an [`EventDataField`] can be turned into an
+/// `EVENT_DATA_DESCRIPTOR` that stays valid for as long as the field itself is alive.
+#[derive(Debug, Clone)]
+pub enum EventDataField {
+    U32(u32),
+    U64(u64),
+    I32(i32),
+    I64(i64),
+    /// A UTF-16, null-terminated string, as ETW string fields expect.
+    Str(U16CString),
+    Guid(GUID),
+    Binary(Vec<u8>),
+}
+
+impl EventDataField {
+    /// Convenience constructor for [`EventDataField::Str`] from any `&str`.
+    pub fn from_str(s: &str) -> Self {
+        Self::Str(U16CString::from_str_truncate(s))
+    }
+
+    /// Build an `EVENT_DATA_DESCRIPTOR` pointing at this field's bytes.
+    ///
+    /// # Safety note
+    /// The returned descriptor borrows `self`'s storage: it is only valid for as long as `self`
+    /// is alive and is not moved, which is why [`EtwProvider::write`](super::EtwProvider::write)
+    /// builds these descriptors right before the `EventWrite` call and never stores them.
+    pub(crate) fn to_event_data_descriptor(&self) -> EVENT_DATA_DESCRIPTOR {
+        let (ptr, len): (*const u8, u32) = match self {
+            Self::U32(v) => (
+                v as *const u32 as *const u8,
+                std::mem::size_of::<u32>() as u32,
+            ),
+            Self::U64(v) => (
+                v as *const u64 as *const u8,
+                std::mem::size_of::<u64>() as u32,
+            ),
+            Self::I32(v) => (
+                v as *const i32 as *const u8,
+                std::mem::size_of::<i32>() as u32,
+            ),
+            Self::I64(v) => (
+                v as *const i64 as *const u8,
+                std::mem::size_of::<i64>() as u32,
+            ),
+            // +1 for the null terminator, *2 because these are UTF-16 code units.
+            Self::Str(v) => (v.as_ptr().cast(), ((v.len() + 1) * 2) as u32),
+            Self::Guid(v) => (
+                v as *const GUID as *const u8,
+                std::mem::size_of::<GUID>() as u32,
+            ),
+            Self::Binary(v) => (v.as_ptr(), v.len() as u32),
+        };
+
+        EVENT_DATA_DESCRIPTOR {
+            Ptr: ptr as u64,
+            Size: len,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read back the bytes a descriptor points at, to check them against what ETW would actually
+    /// copy out of `EVENT_DATA_DESCRIPTOR::Ptr`/`Size`.
+    fn descriptor_bytes(descriptor: &EVENT_DATA_DESCRIPTOR) -> &[u8] {
+        unsafe {
+            // Safety: the caller keeps the `EventDataField` the descriptor was built from alive
+            // and borrowed for at least as long as `descriptor` itself.
+            std::slice::from_raw_parts(descriptor.Ptr as *const u8, descriptor.Size as usize)
+        }
+    }
+
+    #[test]
+    fn u32_points_at_its_own_4_bytes() {
+        let field = EventDataField::U32(0x1234_5678);
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, 4);
+        assert_eq!(descriptor_bytes(&descriptor), 0x1234_5678u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn u64_points_at_its_own_8_bytes() {
+        let field = EventDataField::U64(0x1122_3344_5566_7788);
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, 8);
+        assert_eq!(
+            descriptor_bytes(&descriptor),
+            0x1122_3344_5566_7788u64.to_ne_bytes()
+        );
+    }
+
+    #[test]
+    fn i32_points_at_its_own_4_bytes() {
+        let field = EventDataField::I32(-1);
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, 4);
+        assert_eq!(descriptor_bytes(&descriptor), (-1i32).to_ne_bytes());
+    }
+
+    #[test]
+    fn i64_points_at_its_own_8_bytes() {
+        let field = EventDataField::I64(-1);
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, 8);
+        assert_eq!(descriptor_bytes(&descriptor), (-1i64).to_ne_bytes());
+    }
+
+    #[test]
+    fn guid_points_at_its_own_16_bytes() {
+        let field = EventDataField::Guid(GUID::from_u128(0x1234_5678_9abc_def0_1234_5678_9abc_def0));
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, 16);
+        assert_eq!(descriptor_bytes(&descriptor).len(), 16);
+    }
+
+    #[test]
+    fn binary_points_at_its_own_bytes_with_no_extra_padding() {
+        let field = EventDataField::Binary(vec![1, 2, 3, 4, 5]);
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, 5);
+        assert_eq!(descriptor_bytes(&descriptor), &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn str_size_accounts_for_the_utf16_null_terminator() {
+        // 3 UTF-16 code units + 1 implicit null terminator, *2 bytes each.
+        let field = EventDataField::from_str("abc");
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, (3 + 1) * 2);
+
+        let bytes = descriptor_bytes(&descriptor);
+        assert_eq!(&bytes[0..6], [b'a', 0, b'b', 0, b'c', 0]);
+        assert_eq!(&bytes[6..8], [0, 0], "descriptor must include the null terminator");
+    }
+
+    #[test]
+    fn empty_str_size_is_just_the_null_terminator() {
+        let field = EventDataField::from_str("");
+        let descriptor = field.to_event_data_descriptor();
+
+        assert_eq!(descriptor.Size, 2);
+        assert_eq!(descriptor_bytes(&descriptor), [0, 0]);
+    }
+}
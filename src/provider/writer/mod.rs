@@ -0,0 +1,252 @@
+//! Write-side ETW provider: register this process as an event source and emit events.
+//!
+//! This is the counterpart of the consumer side of this crate (`UserTrace`, `KernelTrace`):
+//! where those consume events from a session, an [`EtwProvider`] lets the current process *emit*
+//! them, so a single crate can be used on both ends of the pipe (write events in one process,
+//! consume them with a `UserTrace` in another).
+use std::ffi::c_void;
+use std::sync::Arc;
+
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::EVENT_DATA_DESCRIPTOR;
+use windows::Win32::System::Diagnostics::Etw::EVENT_DESCRIPTOR;
+
+use crate::native::evntrace::EvntraceNativeError;
+use crate::native::evntrace_write::{self, RegHandle};
+
+pub mod field;
+pub use field::EventDataField;
+
+/// The reason `EnableCallback` was invoked: the controlling session turned this provider's
+/// tracing on or off, or asked it to report its current level/keyword state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnableReason {
+    Enabled,
+    Disabled,
+    CaptureState,
+    /// A control code this crate does not decode yet (reported as-is for forward compatibility).
+    Other(u32),
+}
+
+/// The level/keyword state ETW reports to a provider when [`EnableReason`] changes.
+#[derive(Debug, Clone, Copy)]
+pub struct EnableState {
+    pub reason: EnableReason,
+    pub level: u8,
+    pub match_any_keyword: u64,
+    pub match_all_keyword: u64,
+}
+
+/// A closure invoked whenever a controlling session enables/disables this provider, or changes
+/// its level/keyword filter. See [`EtwProvider::register_with_callback`].
+///
+/// This is called on whatever thread ETW chooses to call back on, so it must be `Send + Sync`,
+/// and should be cheap: it is meant to flip a flag or an atomic, not do expensive work.
+pub type EnableCallback = Box<dyn Fn(EnableState) + Send + Sync + 'static>;
+
+/// Context shared between an [`EtwProvider`] and the native callback ETW invokes on its behalf.
+///
+/// This is `Arc`-owned (rather than embedded directly in `EtwProvider`) so that its address stays
+/// stable across the whole registration, regardless of how the handle wrapping it is moved.
+pub(crate) struct ProviderContext {
+    enable_callback: Option<EnableCallback>,
+}
+
+/// Decode a native `EnableCallback` invocation and forward it to the `EnableCallback` closure
+/// stored in the `ProviderContext` pointed to by `context_ptr`, if any.
+///
+/// Called from [`crate::native::evntrace_write::enable_callback_thunk`].
+pub(crate) fn dispatch_enable_callback(
+    context_ptr: *const c_void,
+    control_code: u32,
+    level: u8,
+    match_any_keyword: u64,
+    match_all_keyword: u64,
+) {
+    use windows::Win32::System::Diagnostics::Etw::{
+        EVENT_CONTROL_CODE_CAPTURE_STATE, EVENT_CONTROL_CODE_DISABLE_PROVIDER,
+        EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+    };
+
+    if context_ptr.is_null() {
+        return;
+    }
+
+    // Safety: `context_ptr` is `Arc::as_ptr(&context)` from `EtwProvider::register*`, and that
+    // `Arc` is kept alive for as long as the provider stays registered (see `EtwProvider::context`).
+    let context = unsafe { &*context_ptr.cast::<ProviderContext>() };
+
+    let Some(callback) = context.enable_callback.as_ref() else {
+        return;
+    };
+
+    let reason = if control_code == EVENT_CONTROL_CODE_ENABLE_PROVIDER.0 {
+        EnableReason::Enabled
+    } else if control_code == EVENT_CONTROL_CODE_DISABLE_PROVIDER.0 {
+        EnableReason::Disabled
+    } else if control_code == EVENT_CONTROL_CODE_CAPTURE_STATE.0 {
+        EnableReason::CaptureState
+    } else {
+        EnableReason::Other(control_code)
+    };
+
+    callback(EnableState {
+        reason,
+        level,
+        match_any_keyword,
+        match_all_keyword,
+    });
+}
+
+/// A provider that this process has registered with ETW, identified by [`EtwProvider::guid`].
+///
+/// Dropping an `EtwProvider` unregisters it (calls `EventUnregister`).
+pub struct EtwProvider {
+    reg_handle: RegHandle,
+    guid: GUID,
+    // Kept alive for as long as the provider is registered, since ETW may call back into it
+    // at any point until `EventUnregister` returns.
+    context: Arc<ProviderContext>,
+}
+
+impl EtwProvider {
+    /// Register the current process as an ETW provider identified by `guid`.
+    pub fn register(guid: GUID) -> Result<Self, EvntraceNativeError> {
+        Self::register_impl(guid, None)
+    }
+
+    /// Register the current process as an ETW provider identified by `guid`, invoking `callback`
+    /// whenever a controlling session enables/disables it or changes its level/keyword filter.
+    ///
+    /// This lets a process cheaply gate expensive instrumentation (e.g. only capture and format a
+    /// stack trace at a log site when a controller turns on a specific keyword) without paying the
+    /// cost while no session is listening.
+    pub fn register_with_callback(guid: GUID, callback: EnableCallback) -> Result<Self, EvntraceNativeError> {
+        Self::register_impl(guid, Some(callback))
+    }
+
+    fn register_impl(guid: GUID, enable_callback: Option<EnableCallback>) -> Result<Self, EvntraceNativeError> {
+        let context = Arc::new(ProviderContext { enable_callback });
+        let context_ptr = Arc::as_ptr(&context).cast::<c_void>();
+
+        let reg_handle = unsafe {
+            // Safety: `context` is kept alive in `self.context` for as long as the provider is
+            // registered, and is only dropped after `EventUnregister` runs (see `Drop` below).
+            evntrace_write::event_register(
+                &guid,
+                Some(crate::native::evntrace_write::enable_callback_thunk),
+                context_ptr,
+            )?
+        };
+
+        Ok(Self {
+            reg_handle,
+            guid,
+            context,
+        })
+    }
+
+    /// The GUID this provider is registered under.
+    pub fn guid(&self) -> GUID {
+        self.guid
+    }
+
+    /// Write a single event described by `event`, with `fields` as its payload.
+    pub fn write(&self, event: &EtwEvent, fields: &[EventDataField]) -> Result<(), EvntraceNativeError> {
+        let descriptors: Vec<EVENT_DATA_DESCRIPTOR> =
+            fields.iter().map(EventDataField::to_event_data_descriptor).collect();
+
+        evntrace_write::event_write(self.reg_handle, &Self::event_descriptor(event), &descriptors)
+    }
+
+    /// Same as [`write`](Self::write), but additionally stamping the event with an `ActivityId`
+    /// and/or a `RelatedActivityId`, so consumers can correlate it with other events from the
+    /// same logical operation (`activity_id`), or link it back to the operation that spawned it
+    /// (`related_activity_id`). Pass `None` for either to omit it.
+    pub fn write_with_activity(
+        &self,
+        event: &EtwEvent,
+        activity_id: Option<&GUID>,
+        related_activity_id: Option<&GUID>,
+        fields: &[EventDataField],
+    ) -> Result<(), EvntraceNativeError> {
+        let descriptors: Vec<EVENT_DATA_DESCRIPTOR> =
+            fields.iter().map(EventDataField::to_event_data_descriptor).collect();
+
+        evntrace_write::event_write_ex(
+            self.reg_handle,
+            &Self::event_descriptor(event),
+            activity_id,
+            related_activity_id,
+            &descriptors,
+        )
+    }
+
+    fn event_descriptor(event: &EtwEvent) -> EVENT_DESCRIPTOR {
+        EVENT_DESCRIPTOR {
+            Id: event.id,
+            Version: event.version,
+            Channel: 0,
+            Level: event.level,
+            Opcode: event.opcode,
+            Task: 0,
+            Keyword: event.keyword,
+        }
+    }
+}
+
+impl Drop for EtwProvider {
+    fn drop(&mut self) {
+        if let Err(e) = evntrace_write::event_unregister(self.reg_handle) {
+            log::warn!(
+                "Failed to unregister ETW provider {:?}: {:?}; leaking its context instead of freeing it",
+                self.guid,
+                e
+            );
+
+            // `event_unregister`'s success is what `ProviderContext`'s safety relies on to
+            // guarantee no `enable_callback_thunk` invocation is still in flight for it (see
+            // `register_impl`). It failed, so we can't assume that here: `self.context` is about
+            // to be dropped right after this function returns, which would free it (and the raw
+            // pointer ETW may still hold) out from under a possible in-flight callback. Leak an
+            // extra strong reference instead, so the context is never freed.
+            std::mem::forget(Arc::clone(&self.context));
+        }
+    }
+}
+
+/// Metadata describing a single event to write: its id, version, level, opcode and keyword.
+///
+/// This mirrors the fields of a native `EVENT_DESCRIPTOR`, minus `Channel`/`Task` which this
+/// crate does not expose on the write side yet.
+#[derive(Debug, Clone, Copy)]
+pub struct EtwEvent {
+    pub id: u16,
+    pub version: u8,
+    pub level: u8,
+    pub opcode: u8,
+    pub keyword: u64,
+}
+
+impl EtwEvent {
+    /// Build a new event descriptor. Defaults `version` and `opcode` to `0` (informational).
+    pub fn new(id: u16, level: u8, keyword: u64) -> Self {
+        Self {
+            id,
+            version: 0,
+            level,
+            opcode: 0,
+            keyword,
+        }
+    }
+
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn with_opcode(mut self, opcode: u8) -> Self {
+        self.opcode = opcode;
+        self
+    }
+}